@@ -1,43 +1,97 @@
+use std::collections::HashMap;
 use std::fmt::Display;
 use std::error::Error;
+use std::time::{Duration, Instant};
 use serde_derive::{Serialize, Deserialize};
 use reqwest::{StatusCode};
 use std::path::Path;
 
+pub const DEFAULT_PROFILE: &str = "default";
 const DEFAULT_SCHEME: &str = "http";
 const DEFAULT_HOST: &str = "localhost:4000";
+const TOKEN_POLL_INTERVAL: Duration = Duration::from_secs(2);
+const TOKEN_POLL_TIMEOUT: Duration = Duration::from_secs(300);
 
 pub struct PastaClient {
     pub client: reqwest::Client,
     pub config: Option<ClientConfig>,
     pub config_path: String,
+    pub profile: String,
 }
 
-#[derive(Deserialize, Serialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct ClientConfig {
     pub token: String,
-    pub host: String
+    pub host: String,
+    #[serde(default = "default_scheme")]
+    pub scheme: String
+}
+
+fn default_scheme() -> String {
+    DEFAULT_SCHEME.to_string()
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct ProfilesFile {
+    #[serde(default)]
+    profiles: HashMap<String, ClientConfig>
+}
+
+#[derive(Deserialize)]
+struct LegacyClientConfig {
+    token: String,
+    host: String
 }
 
 impl ClientConfig {
-    pub fn load_from_file(path: &str) -> Result<ClientConfig, HeyError> {
+    pub fn load_from_file(path: &str, profile: &str) -> Result<ClientConfig, HeyError> {
         let fspath = Path::new(path);
         if !fspath.is_file() {
             return Err(HeyError::NoConfigFound);
         }
 
         let content = std::fs::read_to_string(path).unwrap();
-        let deser: ClientConfig = serde_json::from_str(&content).unwrap();
-        Ok(deser)
+        let profiles_file = load_or_migrate(path, &content);
+
+        profiles_file.profiles.get(profile).cloned().ok_or(HeyError::NoConfigFound)
+    }
+}
+
+/// Parses the profiles TOML file, migrating a legacy single-profile
+/// `.pastaconfig` JSON file into a `[profiles.default]` table the first
+/// time it's loaded.
+fn load_or_migrate(path: &str, content: &str) -> ProfilesFile {
+    if let Ok(profiles_file) = toml::from_str(content) {
+        return profiles_file;
     }
+
+    debug!("Config is not TOML, migrating legacy pastaconfig JSON");
+    let legacy: LegacyClientConfig = serde_json::from_str(content)
+        .expect("config file is neither a valid profiles TOML nor a legacy pastaconfig JSON");
+
+    let mut profiles = HashMap::new();
+    profiles.insert(DEFAULT_PROFILE.to_string(), ClientConfig {
+        token: legacy.token,
+        host: legacy.host,
+        scheme: default_scheme()
+    });
+
+    let migrated = ProfilesFile { profiles };
+    let serialized = toml::to_string(&migrated).expect("could not serialize migrated config");
+    std::fs::write(path, serialized).expect("could not write migrated config");
+    eprintln!("Migrated your config into the \"{}\" profile", DEFAULT_PROFILE);
+
+    migrated
 }
 
 #[derive(Debug)]
 pub enum HeyError {
     NoConfigFound,
-    NotLoggedIn(String),
+    NotLoggedIn(String, String),
     LoginError,
+    LoginTimedOut,
     NoToken,
+    MissingResumePassphrase,
     ServerError(StatusCode),
     RequestError(reqwest::Error)
 }
@@ -60,75 +114,165 @@ impl Error for HeyError {
 
 impl PastaClient {
     pub fn new(client: reqwest::Client, path: String) -> PastaClient {
+        PastaClient::new_with_profile(client, path, DEFAULT_PROFILE.to_string())
+    }
+
+    pub fn new_with_profile(client: reqwest::Client, path: String, profile: String) -> PastaClient {
         PastaClient {
             client,
             config: None,
-            config_path: path
+            config_path: path,
+            profile
         }
     }
 
-    pub fn set_config(&mut self, config: ClientConfig) -> () {
-        self.config = Some(config);
+    pub fn login(&mut self) -> Result<UserInfo, HeyError> {
+        self.with_retry(|c| {
+            let mut resp = c.add_token(c.client.get(&c.get_url("api")))
+                .send()?;
+
+            check_resp(&mut resp)?;
+
+            let resp: UserInfo = resp.json()?;
+
+            Ok(resp)
+        })
     }
-    
-    pub fn login(&self) -> Result<UserInfo, HeyError> {
-        let mut resp = self.add_token(self.client.get(&self.get_url("api")))
-            .send()?;
-        
-        check_resp(&mut resp)?;
 
-        let resp: UserInfo = resp.json()?;
+    pub fn latest(&mut self) -> Result<Pasta, HeyError> {
+        self.with_retry(|c| {
+            let mut resp = c.add_token(c.client.get(&c.get_url("api/latest")))
+                .send()?;
+
+            check_resp(&mut resp)?;
 
-        Ok(resp)
+            let pasta: Pasta = resp.json()?;
+
+            Ok(pasta)
+        })
     }
 
-    pub fn latest(&self) -> Result<Pasta, HeyError> {
-        let mut resp = self.add_token(self.client.get(&self.get_url("api/latest")))
-            .send()?;
-        
-        check_resp(&mut resp)?;
+    pub fn list(&mut self) -> Result<Vec<Pasta>, HeyError> {
+        self.with_retry(|c| {
+            let mut resp = c.add_token(c.client.get(&c.get_url("api/list")))
+                .send()?;
+
+            check_resp(&mut resp)?;
 
-        let pasta: Pasta = resp.json()?;
+            let pastas: Vec<Pasta> = resp.json()?;
 
-        Ok(pasta)
+            Ok(pastas)
+        })
     }
 
-    pub fn list(&self) -> Result<Vec<Pasta>, HeyError> {
-        let mut resp = self.add_token(self.client.get(&self.get_url("api/list")))
-            .send()?;
-        
-        check_resp(&mut resp)?;
+    pub fn post(&mut self, content: String) -> Result<(), HeyError> {
+        self.with_retry(|c| {
+            let msg = CreatePasta {
+                content: content.clone()
+            };
 
-        let pastas: Vec<Pasta> = resp.json()?;
+            let mut resp = c.add_token(c.client.post(&c.get_url("api/create")))
+                .json(&msg)
+                .send()?;
 
-        Ok(pastas)
+            check_resp(&mut resp)
+        })
     }
 
-    pub fn post(&self, content: String) -> Result<(), HeyError> {
-        let msg = CreatePasta {
-            content
-        };
+    pub fn create_stream(&mut self) -> Result<CreateStreamResponse, HeyError> {
+        self.with_retry(|c| {
+            let mut resp = c.add_token(c.client.get(&c.get_url("api/stream")))
+                .send()?;
 
-        let mut resp = self.add_token(self.client.post(&self.get_url("api/create")))
-            .json(&msg)
-            .send()?;
-        
-        check_resp(&mut resp)
+            check_resp(&mut resp)?;
+
+            let resp: CreateStreamResponse = resp.json()?;
+
+            Ok(resp)
+        })
     }
 
-    pub fn create_stream(&self) -> Result<CreateStreamResponse, HeyError> {
-        let mut resp = self.add_token(self.client.get(&self.get_url("api/stream")))
-            .send()?;
+    /// Verifies the current token against the API and transparently
+    /// re-authenticates it if it has expired. Streaming sessions
+    /// (produce/consume/record/play) hand the token to the Phoenix socket
+    /// once at connect time and have no way to react to an auth failure
+    /// over the channel itself, so callers should call this right before
+    /// opening the socket to catch a token that already expired by then.
+    /// It does not cover a token expiring *during* an already-open session.
+    pub fn ensure_fresh_token(&mut self) -> Result<(), HeyError> {
+        self.login().map(|_| ())
+    }
+
+    /// Runs `f` against the current token; on a mid-session 403 it transparently
+    /// re-authenticates through the browser-polling flow once and retries `f`.
+    fn with_retry<T>(&mut self, f: impl Fn(&PastaClient) -> Result<T, HeyError>) -> Result<T, HeyError> {
+        match f(self) {
+            Err(HeyError::NotLoggedIn(login_url, state)) => {
+                warn!("Session expired, re-authenticating");
+                self.reauthenticate(login_url, state)?;
+                f(self)
+            },
+            other => other
+        }
+    }
+
+    fn reauthenticate(&mut self, login_url: String, state: String) -> Result<(), HeyError> {
+        eprintln!("Your session expired. Please visit this URL in a browser:\n{}\n", login_url);
+        let token = self.wait_for_login(&state)?;
+        eprintln!("welcome back!");
+
+        let mut config = self.config.take().unwrap_or_else(|| ClientConfig {
+            token: String::new(),
+            host: DEFAULT_HOST.to_string(),
+            scheme: default_scheme()
+        });
+        config.token = token;
+        self.config = Some(config);
+
+        self.save_config().unwrap_or_else(|e| warn!("Could not persist refreshed token: {:?}", e));
+
+        Ok(())
+    }
+
+    /// Polls the companion token endpoint until the browser login for `state`
+    /// completes, bounded by `TOKEN_POLL_TIMEOUT`.
+    pub fn wait_for_login(&self, state: &str) -> Result<String, HeyError> {
+        let started_at = Instant::now();
 
-        check_resp(&mut resp)?;
+        loop {
+            if started_at.elapsed() > TOKEN_POLL_TIMEOUT {
+                return Err(HeyError::LoginTimedOut);
+            }
 
-        let resp: CreateStreamResponse = resp.json()?;
+            match self.poll_token(state)? {
+                Some(token) => return Ok(token),
+                None => std::thread::sleep(TOKEN_POLL_INTERVAL)
+            }
+        }
+    }
 
-        Ok(resp)
+    fn poll_token(&self, state: &str) -> Result<Option<String>, HeyError> {
+        let mut resp = self.client.get(&self.get_url(&format!("api/token?state={}", state)))
+            .send()?;
+
+        match resp.status() {
+            StatusCode::OK => {
+                let parsed: TokenPollResponse = resp.json()?;
+                Ok(parsed.token)
+            },
+            StatusCode::ACCEPTED | StatusCode::NOT_FOUND => Ok(None),
+            status => Err(HeyError::ServerError(status))
+        }
     }
 
     pub fn get_socket_url(&self) -> Result<String, HeyError> {
-        Ok("ws://localhost:4000/socket".to_string())
+        match &self.config {
+            Some(c) => {
+                let ws_scheme = if c.scheme == "https" { "wss" } else { "ws" };
+                Ok(format!("{}://{}/socket", ws_scheme, c.host))
+            },
+            None => Ok(format!("ws://{}/socket", DEFAULT_HOST))
+        }
     }
 
     pub fn get_token(&self) -> Option<String> {
@@ -141,7 +285,15 @@ impl PastaClient {
 
     pub fn save_config(&self) -> Result<(), Box<Error>> {
         if let Some(conf) = &self.config {
-            let ser = serde_json::to_string(&conf)?;
+            let mut profiles_file = std::fs::read_to_string(&self.config_path)
+                .ok()
+                .and_then(|content| toml::from_str(&content).ok())
+                .unwrap_or_default();
+
+            let ProfilesFile { profiles } = &mut profiles_file;
+            profiles.insert(self.profile.clone(), conf.clone());
+
+            let ser = toml::to_string(&profiles_file)?;
             std::fs::write(&self.config_path, &ser)?;
             Ok(())
         } else {
@@ -159,7 +311,7 @@ impl PastaClient {
 
     fn get_url(&self, url: &str) -> String {
         match self.config {
-            Some(ref c) => format!("{}://{}/{}", DEFAULT_SCHEME, c.host, url),
+            Some(ref c) => format!("{}://{}/{}", c.scheme, c.host, url),
             None => format!("{}://{}/{}", DEFAULT_SCHEME, DEFAULT_HOST, url)
         }
     }
@@ -181,7 +333,13 @@ pub struct Pasta {
 
 #[derive(Deserialize)]
 pub struct LoginResponse {
-    pub login_url: String
+    pub login_url: String,
+    pub state: String
+}
+
+#[derive(Deserialize)]
+struct TokenPollResponse {
+    token: Option<String>
 }
 
 #[derive(Deserialize)]
@@ -201,7 +359,7 @@ fn check_resp(resp: &mut reqwest::Response) -> Result<(), HeyError> {
             Ok(()),
         StatusCode::FORBIDDEN => {
             let r: LoginResponse = resp.json()?;
-            Err(HeyError::NotLoggedIn(r.login_url))
+            Err(HeyError::NotLoggedIn(r.login_url, r.state))
         },
         status =>
             Err(HeyError::ServerError(status))
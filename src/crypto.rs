@@ -0,0 +1,64 @@
+use aes_gcm::aead::{Aead, NewAead};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::Argon2;
+use rand::RngCore;
+
+pub const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+
+/// Derives a stream's AES-256-GCM key from a passphrase and encrypts/decrypts
+/// the `bytes` frames that `streams::produce`/`streams::consume` exchange.
+pub struct StreamCipher {
+    cipher: Aes256Gcm,
+}
+
+impl StreamCipher {
+    pub fn generate_salt() -> [u8; SALT_LEN] {
+        let mut salt = [0u8; SALT_LEN];
+        rand::thread_rng().fill_bytes(&mut salt);
+        salt
+    }
+
+    pub fn from_passphrase(passphrase: &str, salt: &[u8]) -> StreamCipher {
+        let mut key_bytes = [0u8; 32];
+        Argon2::default()
+            .hash_password_into(passphrase.as_bytes(), salt, &mut key_bytes)
+            .expect("argon2id key derivation failed");
+
+        let key = Key::from_slice(&key_bytes);
+        StreamCipher {
+            cipher: Aes256Gcm::new(key),
+        }
+    }
+
+    /// Encrypts `plaintext` with a fresh random nonce, returning `nonce || ciphertext || tag`.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Vec<u8> {
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        rand::thread_rng().fill_bytes(&mut nonce_bytes);
+        let nonce = Nonce::from_slice(&nonce_bytes);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(nonce, plaintext)
+            .expect("aes-256-gcm encryption failed");
+
+        let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(&nonce_bytes);
+        out.extend_from_slice(&ciphertext);
+        out
+    }
+
+    /// Splits off the leading nonce and authenticates/decrypts the remainder.
+    /// Returns `Err(())` on a tag-verification failure; callers must never
+    /// write the bytes to stdout in that case.
+    pub fn decrypt(&self, frame: &[u8]) -> Result<Vec<u8>, ()> {
+        if frame.len() < NONCE_LEN {
+            return Err(());
+        }
+
+        let (nonce_bytes, ciphertext) = frame.split_at(NONCE_LEN);
+        let nonce = Nonce::from_slice(nonce_bytes);
+
+        self.cipher.decrypt(nonce, ciphertext).map_err(|_| ())
+    }
+}
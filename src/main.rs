@@ -8,15 +8,18 @@ use term_size as ts;
 
 mod streams;
 mod client;
+mod crypto;
+mod pty;
 
-use self::client::{PastaClient, HeyError, ClientConfig};
+use self::client::{PastaClient, HeyError, ClientConfig, DEFAULT_PROFILE};
 
 const LOGIN_NAME: &str = "login";
 const LIST_NAME: &str = "list";
 const PRODUCE_NAME: &str = "produce";
 const CONSUME_NAME: &str = "consume";
+const RECORD_NAME: &str = "record";
+const PLAY_NAME: &str = "play";
 
-const DEFAULT_HOST: &str = "localhost:4000";
 const DEFAULT_CONFIG: &str = ".pastaconfig";
 
 fn main() {
@@ -33,35 +36,91 @@ fn main() {
             .value_name("FILE")
             .help("Sets a custom config file")
             .takes_value(true))
+        .arg(Arg::with_name("profile")
+            .short("p")
+            .long("profile")
+            .value_name("NAME")
+            .help("Selects which configured profile to use")
+            .takes_value(true))
         .subcommand(SubCommand::with_name(LOGIN_NAME)
             .about("Logs you into Copypasta"))
         .subcommand(SubCommand::with_name(LIST_NAME)
             .about("Lists your pasta"))
         .subcommand(SubCommand::with_name(PRODUCE_NAME)
-            .about("Produce a stream through your Copypasta account"))
+            .about("Produce a stream through your Copypasta account")
+            .arg(Arg::with_name("window")
+                .long("window")
+                .value_name("N")
+                .help("Number of chunks the producer may send ahead of the last ack")
+                .takes_value(true))
+            .arg(Arg::with_name("resume")
+                .long("resume")
+                .value_name("STREAM")
+                .help("Rejoins an existing stream and resumes from its last-acked offset")
+                .takes_value(true))
+            .arg(Arg::with_name("compress")
+                .long("compress")
+                .help("Gzip-compresses each chunk before it is (optionally) encrypted and base64-encoded"))
+            .arg(Arg::with_name("passphrase")
+                .long("passphrase")
+                .value_name("PASSPHRASE")
+                .help("Encrypts the stream with a passphrase-derived key")
+                .takes_value(true))
+            .arg(Arg::with_name("key-file")
+                .long("key-file")
+                .value_name("FILE")
+                .help("Reads the encryption passphrase from a file")
+                .takes_value(true)
+                .conflicts_with("passphrase")))
         .subcommand(SubCommand::with_name(CONSUME_NAME)
-            .about("Consume a stream created by a Copypasta user"))
+            .about("Consume a stream created by a Copypasta user")
+            .arg(Arg::with_name("passphrase")
+                .long("passphrase")
+                .value_name("PASSPHRASE")
+                .help("Decrypts the stream with a passphrase-derived key")
+                .takes_value(true))
+            .arg(Arg::with_name("key-file")
+                .long("key-file")
+                .value_name("FILE")
+                .help("Reads the decryption passphrase from a file")
+                .takes_value(true)
+                .conflicts_with("passphrase")))
+        .subcommand(SubCommand::with_name(RECORD_NAME)
+            .about("Records your terminal session and streams it through Copypasta"))
+        .subcommand(SubCommand::with_name(PLAY_NAME)
+            .about("Plays back a terminal session recorded with `record`"))
         .get_matches();
 
     let config_file = matches.value_of("config").unwrap_or(DEFAULT_CONFIG);
-
-    match get_app_if_configured(&config_file) {
-        Ok(app) => {
-            if let Some(_) = matches.subcommand_matches(PRODUCE_NAME) {
-                streams::produce(app).unwrap();
-            } else if let Some(_) = matches.subcommand_matches(CONSUME_NAME) {
-                streams::consume(app, "x").unwrap();
+    let profile = matches.value_of("profile").unwrap_or(DEFAULT_PROFILE);
+
+    match get_app_if_configured(&config_file, profile) {
+        Ok(mut app) => {
+            if let Some(sub) = matches.subcommand_matches(PRODUCE_NAME) {
+                let window = sub.value_of("window")
+                    .and_then(|w| w.parse().ok())
+                    .unwrap_or(streams::DEFAULT_WINDOW)
+                    .max(1);
+                let resume = sub.value_of("resume").map(|s| s.to_string());
+                let compress = sub.is_present("compress");
+                streams::produce(app, resolve_passphrase(sub), window, resume, compress).unwrap();
+            } else if let Some(sub) = matches.subcommand_matches(CONSUME_NAME) {
+                streams::consume(app, "x", resolve_passphrase(sub)).unwrap();
+            } else if let Some(_) = matches.subcommand_matches(RECORD_NAME) {
+                pty::record(app).unwrap();
+            } else if let Some(_) = matches.subcommand_matches(PLAY_NAME) {
+                pty::play(app, "x").unwrap();
             } else if let Some(_) = matches.subcommand_matches(LIST_NAME) {
-                handle_list(&app);
+                handle_list(&mut app);
             } else if let Some(_) = matches.subcommand_matches(LOGIN_NAME) {
                 eprintln!("You are already logged in");
             } else {
-                handle_default(&app);
+                handle_default(&mut app);
             }
         },
         Err(HeyError::NoConfigFound) => {
             if let Some(_) = matches.subcommand_matches(LOGIN_NAME) {
-                match get_app(config_file.to_string()) {
+                match get_app(config_file.to_string(), profile.to_string()) {
                    Ok(_app) => {
                        eprintln!("You are now logged in");
                     },
@@ -79,7 +138,7 @@ fn main() {
     }
 }
 
-fn handle_default(app: &PastaClient) {
+fn handle_default(app: &mut PastaClient) {
     if atty::is(AStream::Stdin) {
         match app.latest() {
             Ok(pasta) => println!("{}", pasta.content),
@@ -91,7 +150,7 @@ fn handle_default(app: &PastaClient) {
     }
 }
 
-fn handle_list(app: &PastaClient) {
+fn handle_list(app: &mut PastaClient) {
     let lst = app.list().unwrap();
     let width = ts::dimensions()
         .map(|(w, _)| w)
@@ -107,14 +166,27 @@ fn handle_list(app: &PastaClient) {
     }
 }
 
+fn resolve_passphrase(sub: &clap::ArgMatches) -> Option<String> {
+    if let Some(passphrase) = sub.value_of("passphrase") {
+        return Some(passphrase.to_string());
+    }
+
+    sub.value_of("key-file").map(|path| {
+        std::fs::read_to_string(path)
+            .expect("could not read key file")
+            .trim()
+            .to_string()
+    })
+}
+
 fn read_all_input() -> Result<String, Box<Error>> {
     let mut buffer = String::new();
     io::stdin().read_to_string(&mut buffer)?;
     Ok(buffer)
 }
 
-fn get_app(path: String) -> Result<PastaClient, HeyError> {
-    match get_app_if_configured(&path) {
+fn get_app(path: String, profile: String) -> Result<PastaClient, HeyError> {
+    match get_app_if_configured(&path, &profile) {
         Ok(app) => {
             debug!("App already configured, returning");
             Ok(app)
@@ -122,7 +194,7 @@ fn get_app(path: String) -> Result<PastaClient, HeyError> {
         Err(HeyError::NoConfigFound) => {
             debug!("No configuration found, creating one");
             let client = reqwest::Client::new();
-            let mut new_app = PastaClient::new(client, path);
+            let mut new_app = PastaClient::new_with_profile(client, path, profile);
 
             verify_login(&mut new_app)?;
 
@@ -134,16 +206,17 @@ fn get_app(path: String) -> Result<PastaClient, HeyError> {
     }
 }
 
-fn get_app_if_configured(path: &str) -> Result<PastaClient, HeyError> {
-    let config = ClientConfig::load_from_file(path)?;
-    build_and_verify(path, config)
+fn get_app_if_configured(path: &str, profile: &str) -> Result<PastaClient, HeyError> {
+    let config = ClientConfig::load_from_file(path, profile)?;
+    build_and_verify(path, profile, config)
 }
 
-fn build_and_verify(path: &str, config: ClientConfig) -> Result<PastaClient, HeyError> {
+fn build_and_verify(path: &str, profile: &str, config: ClientConfig) -> Result<PastaClient, HeyError> {
     let mut app = PastaClient {
         config: Some(config),
         client: reqwest::Client::new(),
-        config_path: path.to_string()
+        config_path: path.to_string(),
+        profile: profile.to_string()
     };
 
     if verify_login(&mut app)? {
@@ -153,51 +226,28 @@ fn build_and_verify(path: &str, config: ClientConfig) -> Result<PastaClient, Hey
     Ok(app)
 }
 
+/// Logs in, letting `PastaClient::login` drive the browser-polling flow
+/// (and any transparent re-authentication) on a 403. Returns whether a new
+/// token was obtained, so the caller knows whether to persist the config.
 fn verify_login(app: &mut PastaClient) -> Result<bool, HeyError> {
+    let token_before = app.get_token();
+    let had_token = token_before.is_some();
+
     match app.login() {
-        Ok(_) => {
-            debug!("Login successful, token not updated");
-            Ok(false)
-        },
-        Err(HeyError::NotLoggedIn(login_url)) => {
-            debug!("User not logged in, prompting for token");
-            let token = prompt_token(login_url).unwrap();
-            debug!("Received token \"{}\" from user", token);
-            let host = match app.config {
-                Some(ref c) => c.host.to_string(),
-                None => DEFAULT_HOST.to_string()
-            };
-
-            let config = ClientConfig {
-                token: token.trim().to_string(),
-                host: host
-            };
-
-            debug!("Storing user config: {:?}", config);
-
-            app.set_config(config);
-
-            match app.login() {
-                Ok(user) => {
-                    debug!("Login test successful");
-                    eprintln!("welcome, {}!", user.username);
-                    Ok(true)
-                },
-                Err(e) =>  {
-                    warn!("Login failed");
-                    eprintln!("An error occurred during login: {:?}", e);
-                    Err(e)
-                }
+        Ok(user) => {
+            let token_updated = app.get_token() != token_before;
+            if !token_updated {
+                debug!("Login successful, token not updated");
+            } else if !had_token {
+                eprintln!("welcome, {}!", user.username);
             }
+            Ok(token_updated)
         },
-        Err(e) => Err(e)
+        Err(e) => {
+            warn!("Login failed");
+            eprintln!("An error occurred during login: {:?}", e);
+            Err(e)
+        }
     }
 }
 
-fn prompt_token(login_url: String) -> Result<String, Box<Error>> {
-    eprintln!("You are not logged in. Please visit this URL in a browser:\n{}\n\nThen paste the token here:", login_url);
-    let mut buffer = String::new();
-    io::stdin().read_line(&mut buffer)?;
-    Ok(buffer)
-}
-
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::time::Instant;
+
+use futures::stream::Stream;
+use phoenix::Phoenix;
+use pty::fork::Fork;
+use termios::{Termios, cfmakeraw, tcsetattr, TCSANOW};
+use tokio_core::reactor::Core;
+use websocket::futures::sync::mpsc::channel;
+
+use super::client::{PastaClient, HeyError};
+use super::streams::{into_good, send_msg, send_msg2, StreamEvent};
+
+#[derive(Copy, Clone)]
+enum RecordState {
+    Recording,
+    Done
+}
+
+#[derive(Copy, Clone)]
+enum PlayState {
+    Playing,
+    Done
+}
+
+/// Spawns `$SHELL` in a pseudo-terminal, tees its output to the real
+/// terminal, and produces it into a stream frame-by-frame.
+pub fn record(mut client: PastaClient) -> Result<(), HeyError> {
+    let (sender, emitter) = channel(0);
+    let (callback, messages) = channel(0);
+
+    client.ensure_fresh_token()?;
+
+    let url = client.get_socket_url()?;
+    let token = client.get_token().expect("no token provided");
+
+    let create_stream = client.create_stream()?;
+    let topic_name = format!("streams:{}", create_stream.name);
+    eprintln!("Created recording stream {}", create_stream.name);
+
+    let mut p = HashMap::new();
+    p.insert("token", token.as_str());
+
+    let mut phx = Phoenix::new_with_parameters(&sender, emitter, &callback, &url, &p);
+    let chan = phx.channel(&topic_name).clone();
+    {
+        let mut chan = chan.lock().unwrap();
+        chan.join();
+    }
+
+    send_msg(&chan, "producer_join", "{}");
+
+    let shell = std::env::var("SHELL").unwrap_or_else(|_| "/bin/sh".to_string());
+    let fork = Fork::from_ptmx().expect("failed to open pty");
+
+    if let Ok(mut master) = fork.is_parent() {
+        // Put our real terminal into raw mode for the duration of the
+        // recording so keystrokes pass straight through to the pty instead
+        // of being line-buffered/echoed twice, and copy them into the
+        // master so the spawned shell is actually drivable (a `script`-style
+        // recorder needs this bidirectional loop, not just a read side).
+        let original_termios = Termios::from_fd(libc::STDIN_FILENO).ok();
+        if let Some(orig) = &original_termios {
+            let mut raw = *orig;
+            cfmakeraw(&mut raw);
+            tcsetattr(libc::STDIN_FILENO, TCSANOW, &raw)
+                .unwrap_or_else(|e| warn!("Could not set terminal to raw mode: {:?}", e));
+        }
+
+        let master_fd = master.as_raw_fd();
+        std::thread::spawn(move || {
+            let mut to_master = unsafe { std::fs::File::from_raw_fd(libc::dup(master_fd)) };
+            let mut in_buf = [0u8; 4096];
+
+            loop {
+                match io::stdin().read(&mut in_buf) {
+                    Ok(0) | Err(_) => break,
+                    Ok(n) => {
+                        if to_master.write_all(&in_buf[0..n]).is_err() {
+                            break;
+                        }
+                    }
+                }
+            }
+        });
+
+        let mut chan = chan.lock().unwrap();
+        let mut started_at = Instant::now();
+        let mut in_buf = vec![0; 65536];
+
+        let runner = messages.fold(RecordState::Recording, |state, message| {
+            match (state, into_good(&message.event)) {
+                (RecordState::Recording, StreamEvent::Custom("bytes_requested")) => {
+                    match master.read(&mut in_buf) {
+                        Ok(0) => {
+                            send_msg2(&mut chan, "done", "{}");
+                            Err(())
+                        },
+                        Ok(n) => {
+                            io::stdout().write_all(&in_buf[0..n]).unwrap();
+
+                            let (rows, cols) = term_size::dimensions()
+                                .map(|(w, h)| (h, w))
+                                .unwrap_or((24, 80));
+
+                            let dt_micros = started_at.elapsed().as_micros() as u64;
+                            started_at = Instant::now();
+
+                            let mut payload = String::new();
+                            payload.push_str(&format!("{{\"dt\": {}, \"rows\": {}, \"cols\": {}, \"data\": \"", dt_micros, rows, cols));
+                            base64::encode_config_buf(&in_buf[0..n], base64::STANDARD, &mut payload);
+                            payload.push_str("\"}");
+
+                            send_msg2(&mut chan, "bytes", &payload);
+                            Ok(RecordState::Recording)
+                        },
+                        Err(e) => {
+                            warn!("Error reading from pty, sending done. Error: {:?}", e);
+                            send_msg2(&mut chan, "done", "{\"error\": true}");
+                            Err(())
+                        }
+                    }
+                },
+                _ => Ok(state)
+            }
+        });
+
+        let mut core = Core::new().unwrap();
+        core.run(runner).map(|_| ()).unwrap_or(());
+
+        if let Some(orig) = &original_termios {
+            tcsetattr(libc::STDIN_FILENO, TCSANOW, orig)
+                .unwrap_or_else(|e| warn!("Could not restore terminal settings: {:?}", e));
+        }
+    } else {
+        Command::new(shell).exec();
+    }
+
+    eprintln!("Done recording");
+
+    Ok(())
+}
+
+/// Consumes a `record`-produced stream and replays it with its original timing.
+pub fn play(mut client: PastaClient, stream_name: &str) -> Result<(), HeyError> {
+    let (sender, emitter) = channel(0);
+    let (callback, messages) = channel(0);
+
+    client.ensure_fresh_token()?;
+
+    let url = client.get_socket_url()?;
+    let token = client.get_token().expect("no token provided");
+
+    let mut p = HashMap::new();
+    p.insert("token", token.as_str());
+
+    let mut phx = Phoenix::new_with_parameters(&sender, emitter, &callback, &url, &p);
+    let chan = phx.channel(&format!("streams:{}", stream_name)).clone();
+    {
+        let mut chan = chan.lock().unwrap();
+        chan.join();
+    }
+
+    send_msg(&chan, "consumer_join", "{}");
+    send_msg(&chan, "request_bytes", "{}");
+
+    let mut chan = chan.lock().unwrap();
+    let mut known_dims: Option<(u64, u64)> = None;
+
+    let runner = messages.fold(PlayState::Playing, |state, message| {
+        match (state, into_good(&message.event)) {
+            (PlayState::Playing, StreamEvent::Custom("bytes")) => {
+                let dt = message.payload.get("dt").and_then(|v| v.as_u64()).unwrap_or(0);
+                let rows = message.payload.get("rows").and_then(|v| v.as_u64()).unwrap_or(24);
+                let cols = message.payload.get("cols").and_then(|v| v.as_u64()).unwrap_or(80);
+                let data = message.payload.get("data").and_then(|v| v.as_str()).unwrap();
+                let decoded = base64::decode(data).unwrap();
+
+                std::thread::sleep(std::time::Duration::from_micros(dt));
+
+                if known_dims != Some((rows, cols)) {
+                    print!("\x1b[8;{};{}t", rows, cols);
+                    known_dims = Some((rows, cols));
+                }
+
+                io::stdout().write_all(&decoded).unwrap();
+                io::stdout().flush().unwrap();
+                send_msg2(&mut chan, "request_bytes", "{}");
+                Ok(PlayState::Playing)
+            },
+            (PlayState::Playing, StreamEvent::Custom("no_more_data")) => {
+                eprintln!("Playback finished");
+                Err(())
+            },
+            _ => Ok(state)
+        }
+    });
+
+    let mut core = Core::new().unwrap();
+    core.run(runner).map(|_| ()).unwrap_or(());
+
+    Ok(())
+}
@@ -4,8 +4,140 @@ use phoenix::{Phoenix, Event, PhoenixEvent};
 use websocket::futures::sync::mpsc::channel;
 use tokio_core::reactor::Core;
 use futures::stream::Stream;
+use serde_derive::{Serialize, Deserialize};
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
 
 use super::client::{PastaClient, HeyError};
+use super::crypto::StreamCipher;
+
+pub const DEFAULT_WINDOW: usize = 4;
+
+enum ChunkResult {
+    Chunk(usize, String),
+    Eof,
+    Error
+}
+
+/// Reads one chunk of stdin, gzip-compressing it (if requested) before
+/// encrypting (if a cipher is set) so compression always happens on
+/// plaintext rather than on high-entropy ciphertext.
+fn read_chunk(in_buf: &mut [u8], cipher: &Option<StreamCipher>, compress: bool) -> ChunkResult {
+    match io::stdin().read(in_buf) {
+        Ok(0) => ChunkResult::Eof,
+        Ok(n) => {
+            let payload = if compress {
+                compress_gzip(&in_buf[0..n])
+            } else {
+                in_buf[0..n].to_vec()
+            };
+
+            let chunk = match cipher {
+                Some(c) => c.encrypt(&payload),
+                None => payload
+            };
+
+            let mut encoded = String::new();
+            base64::encode_config_buf(&chunk, base64::STANDARD, &mut encoded);
+            ChunkResult::Chunk(n, encoded)
+        },
+        Err(e) => {
+            warn!("Error reading from stdin: {:?}", e);
+            ChunkResult::Error
+        }
+    }
+}
+
+fn compress_gzip(data: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(data).expect("gzip compression failed");
+    encoder.finish().expect("gzip compression failed")
+}
+
+fn decompress_gzip(data: &[u8]) -> Vec<u8> {
+    let mut decoder = GzDecoder::new(data);
+    let mut out = Vec::new();
+    decoder.read_to_end(&mut out).expect("gzip decompression failed");
+    out
+}
+
+/// Decrypts (if `cipher` is set) and decompresses (if `compressed`) one
+/// `bytes` frame, writes the plaintext to stdout and acks it. Never writes
+/// anything to stdout when tag verification fails.
+fn consume_bytes(decoded: Vec<u8>, seq: Option<u64>, cipher: &Option<StreamCipher>, compressed: bool, chan: &mut phoenix::chan::Channel) -> Result<ConsumerState, ()> {
+    let payload = match cipher {
+        Some(c) => match c.decrypt(&decoded) {
+            Ok(bytes) => bytes,
+            Err(()) => {
+                warn!("Tag verification failed, aborting stream");
+                return Err(());
+            }
+        },
+        None => decoded
+    };
+
+    let plaintext = if compressed { decompress_gzip(&payload) } else { payload };
+
+    io::stdout().write(&plaintext).unwrap();
+
+    match seq {
+        Some(seq) => send_msg2(chan, "ack", &format!("{{\"seq\": {}}}", seq)),
+        None => send_msg2(chan, "request_bytes", "{}")
+    }
+
+    Ok(ConsumerState::Consuming)
+}
+
+fn skip_stdin(bytes: u64) -> Result<(), HeyError> {
+    let mut remaining = bytes;
+    let mut sink = vec![0u8; 65536];
+
+    while remaining > 0 {
+        let want = std::cmp::min(remaining, sink.len() as u64) as usize;
+        match io::stdin().read(&mut sink[0..want]) {
+            Ok(0) => break,
+            Ok(n) => remaining -= n as u64,
+            Err(_) => break
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Default, Debug)]
+struct ResumeState {
+    offset: u64,
+    seq: u64,
+    #[serde(default)]
+    salt: Option<String>,
+    #[serde(default)]
+    compress: bool
+}
+
+impl ResumeState {
+    fn load(stream_name: &str) -> ResumeState {
+        std::fs::read_to_string(Self::path(stream_name))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, stream_name: &str) {
+        let content = serde_json::to_string(self).unwrap();
+        if let Err(e) = std::fs::write(Self::path(stream_name), content) {
+            warn!("Could not persist resume state: {:?}", e);
+        }
+    }
+
+    fn clear(stream_name: &str) {
+        let _ = std::fs::remove_file(Self::path(stream_name));
+    }
+
+    fn path(stream_name: &str) -> String {
+        format!(".pasta-stream-{}.resume", stream_name)
+    }
+}
 
 #[derive(Copy, Clone, Debug)]
 enum ProducerState  {
@@ -17,51 +149,76 @@ enum ProducerState  {
 
 #[derive(Copy, Clone)]
 enum ConsumerState {
+    AwaitingMeta,
     ReadyToConsume,
     Consuming,
     Done
 }
 
-enum StreamEvent<'a> {
+pub(crate) enum StreamEvent<'a> {
     Custom(&'a str),
     Defined(&'a PhoenixEvent)
 }
 
-fn into_good<'a>(evt: &'a Event) -> StreamEvent {
+pub(crate) fn into_good<'a>(evt: &'a Event) -> StreamEvent {
     match evt {
         Event::Custom(x) => StreamEvent::Custom(x.as_ref()),
         Event::Defined(x) => StreamEvent::Defined(x)
     }
 }
 
-fn send_msg(chan: &std::sync::Arc<std::sync::Mutex<phoenix::chan::Channel>>, msg_type: &str, payload: &str) {
+pub(crate) fn send_msg(chan: &std::sync::Arc<std::sync::Mutex<phoenix::chan::Channel>>, msg_type: &str, payload: &str) {
     let mut chan = chan.lock().unwrap();
     let body = serde_json::from_str(payload).unwrap();
     chan.send(Event::Custom(msg_type.to_string()), &body);
 }
 
-fn send_msg2(chan: &mut phoenix::chan::Channel, msg_type: &str, payload: &str) {
+pub(crate) fn send_msg2(chan: &mut phoenix::chan::Channel, msg_type: &str, payload: &str) {
     let body = serde_json::from_str(payload).unwrap();
     chan.send(Event::Custom(msg_type.to_string()), &body);
 }
 
-pub fn produce(client: PastaClient) -> Result<(), HeyError> {
+pub fn produce(mut client: PastaClient, passphrase: Option<String>, window: usize, resume: Option<String>, compress: bool) -> Result<(), HeyError> {
     let (sender, emitter) = channel(0);
     let (callback, messages) = channel(0);
 
+    client.ensure_fresh_token()?;
+
     let url = client.get_socket_url()?;
     debug!("Socket URL will be {}", url);
 
     let token = client.get_token().expect("no token provided");
     debug!("Loaded user token {}", token);
 
-    let create_stream = client.create_stream()?;
-    debug!("Created stream id {}", create_stream.name);
+    let resuming = resume.is_some();
 
-    let topic_name = format!("streams:{}", create_stream.name);
-    debug!("Will join channel {}", topic_name);
+    let (stream_name, resume_state) = match resume {
+        Some(name) => {
+            let state = ResumeState::load(&name);
+            debug!("Resuming stream {} from offset {} (seq {})", name, state.offset, state.seq);
+            (name, state)
+        },
+        None => {
+            let create_stream = client.create_stream()?;
+            debug!("Created stream id {}", create_stream.name);
+            eprintln!("Created stream {}", create_stream.name);
+            (create_stream.name, ResumeState::default())
+        }
+    };
+
+    // A resumed stream must keep whatever salt/compression the original
+    // producer_join negotiated: the already-connected consumer only ever
+    // processes that message once, so a new salt or a flipped compress
+    // flag would permanently break it.
+    let compress = if resuming { resume_state.compress } else { compress };
+
+    if resume_state.offset > 0 {
+        eprintln!("Skipping {} already-acked bytes of input", resume_state.offset);
+        skip_stdin(resume_state.offset)?;
+    }
 
-    eprintln!("Created stream {}", create_stream.name);
+    let topic_name = format!("streams:{}", stream_name);
+    debug!("Will join channel {}", topic_name);
 
     let mut p = HashMap::new();
     p.insert("token", token.as_str());
@@ -73,39 +230,117 @@ pub fn produce(client: PastaClient) -> Result<(), HeyError> {
         chan.join();
     }
 
+    if resume_state.salt.is_some() && passphrase.is_none() {
+        warn!("Resumed stream was encrypted but no --passphrase was given this run");
+        return Err(HeyError::MissingResumePassphrase);
+    }
+
+    let cipher = passphrase.as_ref().map(|pass| {
+        let salt = match &resume_state.salt {
+            Some(salt_b64) => {
+                debug!("Reusing the salt negotiated before this resumed run");
+                base64::decode(salt_b64).expect("corrupt resume state: bad salt")
+            },
+            None => StreamCipher::generate_salt().to_vec()
+        };
+        debug!("Encrypting stream with a passphrase-derived key");
+        (StreamCipher::from_passphrase(pass, &salt), salt)
+    });
+
+    let mut join_fields = Vec::new();
+    if let Some((_, salt)) = &cipher {
+        join_fields.push(format!("\"salt\": \"{}\"", base64::encode(salt)));
+    }
+    if compress {
+        join_fields.push("\"encoding\": \"gzip\"".to_string());
+    }
+    let join_payload = format!("{{{}}}", join_fields.join(", "));
+
     debug!("Sending producer_join message");
-    send_msg(&chan, "producer_join", "{}");
-    let output_buffer = String::new();
-    let input_buffer = vec![0; 1_000_000];
+    send_msg(&chan, "producer_join", &join_payload);
     let mut chan = chan.lock().unwrap();
 
-    let runner = messages.fold((ProducerState::ReadyToProduce, input_buffer, output_buffer), |(state, mut in_buf, mut out_buf), message| {
-        // eprintln!("SAD {:#?} {:?}", message, state); 
+    let salt_b64 = cipher.as_ref().map(|(_, salt)| base64::encode(salt));
+    let cipher = cipher.map(|(c, _)| c);
+
+    let mut in_buf = vec![0; 1_000_000];
+    let mut in_flight: HashMap<u64, u64> = HashMap::new();
+    let mut next_seq = resume_state.seq;
+    let mut offset = resume_state.offset;
+    let mut eof_reached = false;
+
+    // Persist the negotiated salt/compress flag immediately so a `--resume`
+    // started before the first ack still reuses them instead of generating
+    // a fresh salt the already-connected consumer can never learn about.
+    ResumeState { offset, seq: next_seq, salt: salt_b64.clone(), compress }.save(&stream_name);
+
+    let runner = messages.fold(ProducerState::ReadyToProduce, move |state, message| {
         match (state, into_good(&message.event)) {
             (ProducerState::ReadyToProduce, StreamEvent::Custom("bytes_requested")) => {
-                match io::stdin().read(&mut in_buf) {
-                    Ok(0) => {
-                        debug!("No more input, sending done message");
-                        send_msg2(&mut chan, "done", "{}");
-                        Err(())
-                    },
-                    Ok(n) => {
-                        out_buf.clear();
-                        out_buf.push('"');
-                        base64::encode_config_buf(&in_buf[0..n], base64::STANDARD, &mut out_buf);
-                        out_buf.push('"');
-                        debug!("Sending bytes buffer (len {})", out_buf.len());
-                        send_msg2(&mut chan, "bytes", &out_buf);
-                        Ok((ProducerState::ReadyToProduce, in_buf, out_buf))
-                    },
-                    Err(e) => {
-                        warn!("Error reading, sending done. Error: {:?}", e);
-                        send_msg2(&mut chan, "done", "{\"error\": true}");
-                        Err(())
+                while !eof_reached && in_flight.len() < window {
+                    match read_chunk(&mut in_buf, &cipher, compress) {
+                        ChunkResult::Chunk(n, data) => {
+                            in_flight.insert(next_seq, n as u64);
+                            send_msg2(&mut chan, "bytes", &format!("{{\"seq\": {}, \"data\": \"{}\"}}", next_seq, data));
+                            next_seq += 1;
+                        },
+                        ChunkResult::Eof => eof_reached = true,
+                        ChunkResult::Error => {
+                            send_msg2(&mut chan, "done", "{\"error\": true}");
+                            return Err(());
+                        }
+                    }
+                }
+
+                if eof_reached && in_flight.is_empty() {
+                    debug!("No more input, sending done message");
+                    send_msg2(&mut chan, "done", "{}");
+                    ResumeState::clear(&stream_name);
+                    Err(())
+                } else if in_flight.len() >= window {
+                    Ok(ProducerState::WaitingForAck)
+                } else {
+                    Ok(ProducerState::Producing)
+                }
+            },
+            (ProducerState::Producing, StreamEvent::Custom("ack"))
+            | (ProducerState::WaitingForAck, StreamEvent::Custom("ack")) => {
+                let seq = message.payload.get("seq").and_then(|s| s.as_u64());
+                if let Some(seq) = seq {
+                    if let Some(len) = in_flight.remove(&seq) {
+                        offset += len;
+                        let state = ResumeState { offset, seq: next_seq, salt: salt_b64.clone(), compress };
+                        state.save(&stream_name);
+                    }
+                }
+
+                if !eof_reached && in_flight.len() < window {
+                    match read_chunk(&mut in_buf, &cipher, compress) {
+                        ChunkResult::Chunk(n, data) => {
+                            in_flight.insert(next_seq, n as u64);
+                            send_msg2(&mut chan, "bytes", &format!("{{\"seq\": {}, \"data\": \"{}\"}}", next_seq, data));
+                            next_seq += 1;
+                        },
+                        ChunkResult::Eof => eof_reached = true,
+                        ChunkResult::Error => {
+                            send_msg2(&mut chan, "done", "{\"error\": true}");
+                            return Err(());
+                        }
                     }
                 }
+
+                if eof_reached && in_flight.is_empty() {
+                    debug!("All chunks acked and input exhausted, sending done message");
+                    send_msg2(&mut chan, "done", "{}");
+                    ResumeState::clear(&stream_name);
+                    Err(())
+                } else if in_flight.len() >= window {
+                    Ok(ProducerState::WaitingForAck)
+                } else {
+                    Ok(ProducerState::Producing)
+                }
             },
-            _ => Ok((state, in_buf, out_buf))
+            _ => Ok(state)
         }
     });
 
@@ -117,16 +352,18 @@ pub fn produce(client: PastaClient) -> Result<(), HeyError> {
     Ok(())
 }
 
-pub fn consume(client: PastaClient, stream_name: &str) -> Result<(), HeyError> {
+pub fn consume(mut client: PastaClient, stream_name: &str, passphrase: Option<String>) -> Result<(), HeyError> {
     let (sender, emitter) = channel(0);
     let (callback, messages) = channel(0);
-    
+
+    client.ensure_fresh_token()?;
+
     let url = client.get_socket_url()?;
     let token = client.get_token().expect("no token provided");
 
     let mut p = HashMap::new();
     p.insert("token", token.as_str());
-    
+
     let mut phx = Phoenix::new_with_parameters(&sender, emitter, &callback, &url, &p);
     let chan = phx.channel(&format!("streams:{}", stream_name)).clone();
     {
@@ -138,18 +375,49 @@ pub fn consume(client: PastaClient, stream_name: &str) -> Result<(), HeyError> {
     send_msg(&chan, "request_bytes", "{}");
 
     let mut chan = chan.lock().unwrap();
+    let mut cipher: Option<StreamCipher> = None;
+    let mut compressed = false;
 
-    let runner = messages.fold(ConsumerState::Consuming, |state, message| {
+    let runner = messages.fold(ConsumerState::AwaitingMeta, |state, message| {
         // eprintln!("{:#?}", message);
         match (state, into_good(&message.event)) {
-            (ConsumerState::Consuming, StreamEvent::Custom("bytes")) => {
-                // eprintln!("Got some bytes!");
-                let x = message.payload.get("data").unwrap().as_str().unwrap();
-                let decoded = base64::decode(x).unwrap();
-                io::stdout().write(&decoded).unwrap();
-                send_msg2(&mut chan, "request_bytes", "{}");
+            (ConsumerState::AwaitingMeta, StreamEvent::Custom("producer_join")) => {
+                compressed = message.payload.get("encoding").and_then(|s| s.as_str()) == Some("gzip");
+
+                if let Some(salt_b64) = message.payload.get("salt").and_then(|s| s.as_str()) {
+                    let salt = base64::decode(salt_b64).unwrap();
+                    cipher = Some(StreamCipher::from_passphrase(passphrase.as_ref().expect("stream is encrypted but no passphrase was given"), &salt));
+                }
+
                 Ok(ConsumerState::Consuming)
             },
+            (ConsumerState::AwaitingMeta, StreamEvent::Custom("bytes")) => {
+                // No producer_join metadata was seen before the first chunk arrived.
+                // Every producer that tags frames with a "seq" also always sends
+                // producer_join first (it's the same windowed protocol from
+                // chunk0-3 onward), so seeing one here means we joined the channel
+                // after the single producer_join broadcast already went out - not
+                // that the stream is unencrypted/uncompressed. Guessing either way
+                // risks writing ciphertext or raw gzip bytes straight to stdout, so
+                // abort instead. Only a pre-windowing producer that never sends a
+                // seq at all predates both encryption and compression, so that case
+                // alone is safe to assume is plaintext and uncompressed.
+                let seq = message.payload.get("seq").and_then(|s| s.as_u64());
+                if seq.is_some() {
+                    warn!("Received stream bytes before producer_join metadata, aborting rather than guessing at decoding");
+                    return Err(());
+                }
+
+                let data = message.payload.get("data").unwrap().as_str().unwrap();
+                let decoded = base64::decode(data).unwrap();
+                consume_bytes(decoded, seq, &None, false, &mut chan)
+            },
+            (ConsumerState::Consuming, StreamEvent::Custom("bytes")) => {
+                let seq = message.payload.get("seq").and_then(|s| s.as_u64());
+                let data = message.payload.get("data").unwrap().as_str().unwrap();
+                let decoded = base64::decode(data).unwrap();
+                consume_bytes(decoded, seq, &cipher, compressed, &mut chan)
+            },
             (ConsumerState::Consuming, StreamEvent::Custom("no_more_data")) => {
                 eprintln!("Ate all the bytes");
                 Err(())